@@ -1,8 +1,9 @@
-use std::io::{self, ErrorKind};
-use std::fs::{File, Metadata, read_link};
+use std::io::{self, Read, Write, ErrorKind};
+use std::fs::{self, File, Metadata, read_link};
 use std::path::{Path, PathBuf};
 use std::os::unix::fs::{PermissionsExt, MetadataExt};
-use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use libc::{uid_t, gid_t};
 use quire::ast::{Ast, Tag};
@@ -78,9 +79,15 @@ impl BuildStep for Depends {
         -> Result<(), VersionError>
     {
         let path = Path::new("/work").join(&self.path);
-        let filter = create_path_filter(&self.rules, self.no_default_rules,
-            &self.ignore_regex, &self.include_regex)?;
-        hash_path(hash, &path, &filter, |h, p, st| {
+        let (filter, expanded) = create_path_filter(&self.rules,
+            self.no_default_rules,
+            &self.ignore_regex, &self.include_regex, &path)?;
+        for rule in &expanded {
+            hash.field("rule", rule);
+        }
+        let signature = filter_signature(&expanded, self.no_default_rules,
+            &self.ignore_regex, &self.include_regex);
+        hash_path(hash, &path, &filter, &signature, |h, p, st, cache| {
             h.field("filename", p);
             // We hash only executable flag for files
             // as mode depends on the host system umask
@@ -89,7 +96,7 @@ impl BuildStep for Depends {
                 let is_executable = mode & EXE_CHECK_MASK > 0;
                 h.field("is_executable", is_executable);
             }
-            hash_file_content(h, p, st)
+            hash_file_content(h, p, st, cache)
                 .map_err(|e| VersionError::Io(e, PathBuf::from(p)))?;
             Ok(())
         })?;
@@ -164,14 +171,20 @@ impl BuildStep for Copy {
     {
         let ref src = self.source;
         if src.starts_with("/work") {
-            let filter = create_path_filter(&self.rules, self.no_default_rules,
-                &self.ignore_regex, &self.include_regex)?;
-            hash_path(hash, src, &filter, |h, p, st| {
+            let (filter, expanded) = create_path_filter(&self.rules,
+                self.no_default_rules,
+                &self.ignore_regex, &self.include_regex, src)?;
+            for rule in &expanded {
+                hash.field("rule", rule);
+            }
+            let signature = filter_signature(&expanded, self.no_default_rules,
+                &self.ignore_regex, &self.include_regex);
+            hash_path(hash, src, &filter, &signature, |h, p, st, cache| {
                 h.field("filename", p);
                 h.opt_field("mode", &self.calc_mode(st));
                 h.field("uid", self.owner_uid.unwrap_or(st.uid()));
                 h.field("gid", self.owner_gid.unwrap_or(st.gid()));
-                hash_file_content(h, p, st)
+                hash_file_content(h, p, st, cache)
                     .map_err(|e| VersionError::Io(e, PathBuf::from(p)))?;
                 Ok(())
             })?;
@@ -209,9 +222,9 @@ impl BuildStep for Copy {
                         self.owner_uid, self.owner_gid,
                         self.calc_mode(typ))
                     .context((src, dest))?;
-                let filter = create_path_filter(
+                let (filter, _) = create_path_filter(
                     &self.rules, self.no_default_rules,
-                    &self.ignore_regex, &self.include_regex)?;
+                    &self.ignore_regex, &self.include_regex, src)?;
                 let mut processed_paths = HashSet::new();
                 filter.walk(src, |iter| {
                     for entry in iter {
@@ -251,23 +264,32 @@ impl BuildStep for Copy {
     }
 }
 
-fn hash_path<F>(hash: &mut Digest, path: &Path, filter: &PathFilter, hash_file: F)
+fn hash_path<F>(hash: &mut Digest, path: &Path, filter: &PathFilter,
+    signature: &str, hash_file: F)
     -> Result<(), VersionError>
-    where F: Fn(&mut Digest, &Path, &Metadata) -> Result<(), VersionError>
+    where F: Fn(&mut Digest, &Path, &Metadata, &mut HashCache)
+        -> Result<(), VersionError>
 {
+    let mut cache = HashCache::load(path, signature);
     match path.symlink_metadata() {
         Ok(ref meta) if meta.file_type().is_dir() => {
-            hash_file(hash, path, meta)?;
+            hash_file(hash, path, meta, &mut cache)?;
             let all_rel_paths = get_sorted_rel_paths(path, filter)?;
             for rel_path in &all_rel_paths {
                 let ref abs_path = path.join(rel_path);
+                // Never hash our own cache, regardless of the rule set: a
+                // step with `no_default_rules` would otherwise walk the index
+                // (whose bytes change every build) and never stabilize.
+                if abs_path.starts_with(CACHE_DIR) {
+                    continue;
+                }
                 let stat = abs_path.symlink_metadata()
                     .map_err(|e| VersionError::Io(e, PathBuf::from(abs_path)))?;
-                hash_file(hash, abs_path, &stat)?;
+                hash_file(hash, abs_path, &stat, &mut cache)?;
             }
         }
         Ok(ref meta) => {
-            hash_file(hash, path, meta)?;
+            hash_file(hash, path, meta, &mut cache)?;
         }
         Err(ref e) if e.kind() == ErrorKind::NotFound => {
             return Err(VersionError::New);
@@ -276,6 +298,7 @@ fn hash_path<F>(hash: &mut Digest, path: &Path, filter: &PathFilter, hash_file:
             return Err(VersionError::Io(e, path.into()));
         }
     }
+    cache.save();
     Ok(())
 }
 
@@ -297,12 +320,15 @@ fn get_sorted_rel_paths(path: &Path, filter: &PathFilter)
     })
 }
 
-fn hash_file_content(hash: &mut Digest, path: &Path, stat: &Metadata)
+fn hash_file_content(hash: &mut Digest, path: &Path, stat: &Metadata,
+    cache: &mut HashCache)
     -> Result<(), io::Error>
 {
     if stat.file_type().is_file() {
-        let mut file = File::open(&path)?;
-        hash.file(&path, &mut file)?;
+        // Feed the content hash rather than the raw bytes, so that a warm
+        // cache can supply it from a previous build without opening the file.
+        let content = cache.content_hash(path, stat)?;
+        hash.field("content", &content);
     } else if stat.file_type().is_symlink() {
         let data = read_link(path)?;
         hash.field("symlink", data);
@@ -311,8 +337,9 @@ fn hash_file_content(hash: &mut Digest, path: &Path, stat: &Metadata)
 }
 
 fn create_path_filter(rules: &Vec<String>, no_default_rules: Option<bool>,
-    ignore_regex: &Option<String>, include_regex: &Option<String>)
-    -> Result<PathFilter, String>
+    ignore_regex: &Option<String>, include_regex: &Option<String>,
+    base: &Path)
+    -> Result<(PathFilter, Vec<String>), String>
 {
     if (!rules.is_empty() || no_default_rules.is_some()) &&
         (ignore_regex.is_some() || include_regex.is_some())
@@ -321,23 +348,341 @@ fn create_path_filter(rules: &Vec<String>, no_default_rules: Option<bool>,
             "You must specify either rules or regular expressions \
              but not both"));
     }
-    Ok(if !rules.is_empty() {
-        let mut all_rules: Vec<&str> = vec!();
+    if !rules.is_empty() {
+        let mut all_rules: Vec<String> = vec!();
         if !no_default_rules.unwrap_or(false)  {
-            all_rules.extend(DEFAULT_IGNORE_RULES);
+            all_rules.extend(DEFAULT_IGNORE_RULES.iter().map(|r| r.to_string()));
         }
         for rule in rules {
-            if !rule.starts_with('!') && !rule.starts_with('/') {
-                return Err(format!(
-                    "Relative paths are allowed only for excluding rules"));
-            }
-            all_rules.push(&rule);
+            expand_rule(rule, base, &mut all_rules)?;
         }
-        PathFilter::glob(&all_rules[..])
+        let refs: Vec<&str> = all_rules.iter().map(|r| &r[..]).collect();
+        let filter = PathFilter::glob(&refs[..])
+            .map_err(|e| format!("Can't compile copy filter: {}", e))?;
+        // Return the fully resolved rule set so the caller can feed it into
+        // the step hash: the contents of @include / @gitignore files end up
+        // inlined here, so changing one busts the container version.
+        Ok((filter, all_rules))
     } else {
-        PathFilter::regex(
+        let filter = PathFilter::regex(
             ignore_regex.as_ref().map(String::as_ref)
                 .or(Some(DEFAULT_IGNORE_REGEX)),
             include_regex.as_ref())
-    }.map_err(|e| format!("Can't compile copy filter: {}", e))?)
+            .map_err(|e| format!("Can't compile copy filter: {}", e))?;
+        Ok((filter, Vec::new()))
+    }
+}
+
+// Expand a single rule entry into zero or more concrete glob rules, handling
+// the `@include`, `@gitignore`/`@dockerignore` and `%unset` directives.
+fn expand_rule(rule: &str, base: &Path, out: &mut Vec<String>)
+    -> Result<(), String>
+{
+    if let Some(arg) = directive_arg(rule, "@include") {
+        for line in read_rule_file(&base.join(arg))? {
+            push_glob_rule(&line, out)?;
+        }
+    } else if rule == "@gitignore" || directive_arg(rule, "@gitignore").is_some() {
+        let file = directive_arg(rule, "@gitignore")
+            .map(|a| base.join(a))
+            .unwrap_or_else(|| base.join(".gitignore"));
+        import_ignore_file(&file, out)?;
+    } else if rule == "@dockerignore" ||
+        directive_arg(rule, "@dockerignore").is_some()
+    {
+        let file = directive_arg(rule, "@dockerignore")
+            .map(|a| base.join(a))
+            .unwrap_or_else(|| base.join(".dockerignore"));
+        import_ignore_file(&file, out)?;
+    } else if let Some(pat) = directive_arg(rule, "%unset") {
+        // Drop a previously added rule, including one of the defaults.
+        out.retain(|r| r != pat);
+    } else {
+        push_glob_rule(rule, out)?;
+    }
+    Ok(())
+}
+
+// Returns the trimmed argument of a `<name> <arg>` directive, or None if the
+// rule is not that directive.
+fn directive_arg<'a>(rule: &'a str, name: &str) -> Option<&'a str> {
+    if rule.starts_with(name) && rule[name.len()..].starts_with(' ') {
+        let arg = rule[name.len()..].trim();
+        if !arg.is_empty() {
+            return Some(arg);
+        }
+    }
+    None
+}
+
+fn push_glob_rule(rule: &str, out: &mut Vec<String>) -> Result<(), String> {
+    if !rule.starts_with('!') && !rule.starts_with('/') {
+        return Err(format!(
+            "Relative paths are allowed only for excluding rules"));
+    }
+    out.push(rule.to_string());
+    Ok(())
+}
+
+// Read newline-separated glob rules from a file, skipping blank lines and
+// `#` comments.  Used for `@include`d shared `.vaggaignore` files.
+fn read_rule_file(path: &Path) -> Result<Vec<String>, String> {
+    let mut content = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut content))
+        .map_err(|e| format!("Can't read rule file {:?}: {}", path, e))?;
+    Ok(content.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.to_string())
+        .collect())
+}
+
+// Import an existing `.gitignore`/`.dockerignore` verbatim, translating its
+// semantics into our glob rules: an ordinary pattern excludes, a negated
+// `!pattern` re-includes it (root-relative, as our includes require).
+fn import_ignore_file(path: &Path, out: &mut Vec<String>)
+    -> Result<(), String>
+{
+    let mut content = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut content))
+        .map_err(|e| format!("Can't read ignore file {:?}: {}", path, e))?;
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('!') {
+            out.push(format!("/{}", line[1..].trim_start_matches('/')));
+        } else {
+            out.push(format!("!{}", line));
+        }
+    }
+    Ok(())
+}
+
+fn filter_signature(rules: &Vec<String>, no_default_rules: Option<bool>,
+    ignore_regex: &Option<String>, include_regex: &Option<String>)
+    -> String
+{
+    // A stable fingerprint of everything that influences which files are
+    // walked.  It is stored in the cache header so that changing the rule set
+    // or the regexes invalidates every cached record in one shot.
+    format!("{:?}\0{:?}\0{:?}\0{:?}",
+        rules, no_default_rules, ignore_regex, include_regex)
+}
+
+// Granularity of the timestamps we keep in the cache, in seconds.  A file
+// touched within this window of the moment the cache was written is
+// "ambiguous": a later modification in the same second would not bump the
+// truncated mtime, so we must never trust such an entry.
+const CACHE_MTIME_GRANULARITY: i64 = 1;
+
+const CACHE_MAGIC: &'static [u8] = b"VGHC\x01";
+
+const CACHE_DIR: &'static str = "/work/.vagga/.cache/hashes";
+
+#[derive(Clone)]
+struct CacheEntry {
+    inode: u64,
+    size: u64,
+    mtime: i64,
+    hash: Vec<u8>,
+}
+
+/// A dirstate-style on-disk cache mapping each absolute path to the
+/// `(inode, size, truncated-mtime, content-hash)` observed last time.
+///
+/// Lookups that match inode, size and mtime return the stored content hash
+/// without re-reading the file, turning a warm `hash` pass from O(bytes) into
+/// O(files stat'd).  The cache is keyed by the filter signature so a changed
+/// rule set discards it wholesale.
+struct HashCache {
+    file: PathBuf,
+    signature: String,
+    old: HashMap<PathBuf, CacheEntry>,
+    fresh: HashMap<PathBuf, CacheEntry>,
+}
+
+impl HashCache {
+    fn load(root: &Path, signature: &str) -> HashCache {
+        let file = cache_file_for(root, signature);
+        let old = read_cache(&file, signature).unwrap_or_else(HashMap::new);
+        HashCache {
+            file: file,
+            signature: signature.to_string(),
+            old: old,
+            fresh: HashMap::new(),
+        }
+    }
+
+    fn content_hash(&mut self, path: &Path, stat: &Metadata)
+        -> Result<Vec<u8>, io::Error>
+    {
+        let inode = stat.ino();
+        let size = stat.size();
+        let mtime = stat.mtime();
+        if let Some(entry) = self.old.get(path) {
+            if entry.inode == inode && entry.size == size &&
+                entry.mtime == mtime
+            {
+                self.fresh.insert(path.to_path_buf(), entry.clone());
+                return Ok(entry.hash.clone());
+            }
+        }
+        let hash = file_content_hash(path)?;
+        self.fresh.insert(path.to_path_buf(), CacheEntry {
+            inode: inode,
+            size: size,
+            mtime: mtime,
+            hash: hash.clone(),
+        });
+        Ok(hash)
+    }
+
+    fn save(&self) {
+        // The cache is an optimization only, so any error writing it is
+        // swallowed -- the next build simply recomputes from scratch.
+        let _ = write_cache(&self.file, &self.signature, &self.fresh);
+    }
+}
+
+fn file_content_hash(path: &Path) -> Result<Vec<u8>, io::Error> {
+    let mut file = File::open(path)?;
+    let mut digest = Digest::new();
+    digest.file(path, &mut file)?;
+    Ok(digest.result())
+}
+
+fn cache_file_for(root: &Path, signature: &str) -> PathBuf {
+    let mut name = String::new();
+    for ch in root.to_string_lossy().chars() {
+        match ch {
+            '/' => name.push('%'),
+            '%' => name.push_str("%%"),
+            c => name.push(c),
+        }
+    }
+    // Distinct rule sets hashing the same root (the usual Depends + Copy on
+    // /work pair) must not share one index, or they'd invalidate each other's
+    // cache every build.  Disambiguate the file by the signature too.
+    name.push('.');
+    name.push_str(&short_hash(signature));
+    Path::new(CACHE_DIR).join(name)
+}
+
+// A short, stable (FNV-1a) fingerprint of the signature, used only to keep
+// caches for different rule sets in separate files.
+fn short_hash(data: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in data.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+fn read_cache(path: &Path, signature: &str)
+    -> Option<HashMap<PathBuf, CacheEntry>>
+{
+    let mut buf = Vec::new();
+    File::open(path).ok()?.read_to_end(&mut buf).ok()?;
+    let mut cur = Cursor::new(&buf);
+    if cur.take(CACHE_MAGIC.len())? != CACHE_MAGIC {
+        return None;
+    }
+    let sig = cur.take_field()?;
+    if sig != signature.as_bytes() {
+        return None;
+    }
+    let count = cur.take_u32()? as usize;
+    let mut map = HashMap::with_capacity(count);
+    for _ in 0..count {
+        let path = PathBuf::from(
+            String::from_utf8(cur.take_field()?.to_vec()).ok()?);
+        let entry = CacheEntry {
+            inode: cur.take_u64()?,
+            size: cur.take_u64()?,
+            mtime: cur.take_u64()? as i64,
+            hash: cur.take_field()?.to_vec(),
+        };
+        map.insert(path, entry);
+    }
+    Some(map)
+}
+
+fn write_cache(path: &Path, signature: &str,
+    entries: &HashMap<PathBuf, CacheEntry>)
+    -> Result<(), io::Error>
+{
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    // Drop ambiguous entries: a file modified within the timestamp
+    // granularity of *now* could be modified again this second without
+    // changing its mtime, so it must stay dirty next time.
+    let durable: Vec<_> = entries.iter()
+        .filter(|&(_, e)| now - e.mtime >= CACHE_MTIME_GRANULARITY)
+        .collect();
+
+    let mut out = Vec::new();
+    out.extend_from_slice(CACHE_MAGIC);
+    put_field(&mut out, signature.as_bytes());
+    out.extend_from_slice(&(durable.len() as u32).to_le_bytes());
+    for &(path, entry) in &durable {
+        put_field(&mut out, path.to_string_lossy().as_bytes());
+        out.extend_from_slice(&entry.inode.to_le_bytes());
+        out.extend_from_slice(&entry.size.to_le_bytes());
+        out.extend_from_slice(&(entry.mtime as u64).to_le_bytes());
+        put_field(&mut out, &entry.hash);
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Write atomically so a concurrent build never reads a half-written index.
+    // The temporary name keeps the full index name and adds the pid, so no
+    // two writers ever share one temporary file.
+    let mut tmp_name = path.as_os_str().to_os_string();
+    tmp_name.push(format!(".tmp.{}", ::std::process::id()));
+    let tmp = PathBuf::from(tmp_name);
+    File::create(&tmp)?.write_all(&out)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn put_field(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(buf: &'a [u8]) -> Cursor<'a> {
+        Cursor { buf: buf, pos: 0 }
+    }
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.pos + n > self.buf.len() {
+            return None;
+        }
+        let slice = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Some(slice)
+    }
+    fn take_u32(&mut self) -> Option<u32> {
+        let b = self.take(4)?;
+        Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    fn take_u64(&mut self) -> Option<u64> {
+        let b = self.take(8)?;
+        Some(u64::from_le_bytes(
+            [b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+    fn take_field(&mut self) -> Option<&'a [u8]> {
+        let len = self.take_u32()? as usize;
+        self.take(len)
+    }
 }
@@ -0,0 +1,65 @@
+use std::io;
+use std::fs::{self, Metadata, Permissions, read_link, create_dir};
+use std::ffi::CString;
+use std::path::Path;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::os::unix::ffi::OsStrExt;
+
+use libc::{self, uid_t, gid_t};
+
+use container::util::clone_file;
+
+
+/// Copy a single filesystem entry from `src` to `dest` without recursing.
+///
+/// Directories are created empty -- the caller walks their contents -- and
+/// symlinks are recreated verbatim.  Regular files go through `clone_file`,
+/// so on a copy-on-write filesystem the `Copy` build step shares extents with
+/// the source instead of duplicating the bytes.  The optional `mode` and
+/// owner are applied to the destination afterwards.
+pub fn shallow_copy(src: &Path, typ: &Metadata, dest: &Path,
+    owner_uid: Option<uid_t>, owner_gid: Option<gid_t>, mode: Option<u32>)
+    -> Result<(), io::Error>
+{
+    let ftyp = typ.file_type();
+    if ftyp.is_dir() {
+        if !dest.is_dir() {
+            create_dir(dest)?;
+        }
+    } else if ftyp.is_symlink() {
+        let target = read_link(src)?;
+        symlink(&target, dest)?;
+    } else {
+        clone_file(src, dest)?;
+    }
+    // We never change permissions or ownership through a symlink, as that
+    // would touch the target rather than the link itself.
+    if !ftyp.is_symlink() {
+        if let Some(mode) = mode {
+            fs::set_permissions(dest, Permissions::from_mode(mode))?;
+        }
+    }
+    if owner_uid.is_some() || owner_gid.is_some() {
+        set_owner(dest, owner_uid, owner_gid)?;
+    }
+    Ok(())
+}
+
+fn set_owner(path: &Path, uid: Option<uid_t>, gid: Option<gid_t>)
+    -> Result<(), io::Error>
+{
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput,
+            "Path contains a nul byte"))?;
+    // A -1 argument leaves the respective id unchanged.
+    let rc = unsafe {
+        libc::lchown(cpath.as_ptr(),
+            uid.unwrap_or(uid_t::max_value()),
+            gid.unwrap_or(gid_t::max_value()))
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
@@ -2,6 +2,7 @@ use std::io::{stdout, stderr, Write};
 use std::rc::Rc;
 
 use argparse::{ArgumentParser, StoreTrue, StoreConst};
+use rustc_serialize::json;
 
 use config::Config;
 use launcher::sphinx;
@@ -11,6 +12,23 @@ enum Format {
     Text,
     Zsh,
     Sphinx,
+    Json,
+}
+
+#[derive(RustcEncodable)]
+struct CommandInfo {
+    name: String,
+    description: String,
+    builtin: bool,
+    hidden: bool,
+    source: Option<String>,
+    containers: Vec<String>,
+}
+
+#[derive(RustcEncodable)]
+struct ContainerInfo {
+    name: String,
+    source: Option<String>,
 }
 
 
@@ -42,7 +60,9 @@ pub fn print_list(config: &Config, mut args: Vec<String>)
             .add_option(&["--zsh"], StoreConst(Format::Zsh),
                 "Use zsh completion compatible format")
             .add_option(&["--sphinx"], StoreConst(Format::Sphinx),
-                "Print sphinx-friendly restructured text (experimental)");
+                "Print sphinx-friendly restructured text (experimental)")
+            .add_option(&["--json"], StoreConst(Format::Json),
+                "Print a machine-readable JSON array");
         ap.refer(&mut verbose)
             .add_option(&["-v", "--verbose"], StoreTrue,
                 "Verbose output (show source files
@@ -53,11 +73,23 @@ pub fn print_list(config: &Config, mut args: Vec<String>)
         }
     }
     if containers {
-        for (cname, container) in config.containers.iter() {
-            println!("{}", cname);
-            if let Some(ref src) = container.source {
-                if verbose {
-                    println!("{:19} (from {:?})", " ", &src);
+        if format == Format::Json {
+            let infos: Vec<_> = config.containers.iter()
+                .map(|(cname, container)| ContainerInfo {
+                    name: cname.clone(),
+                    source: container.source.as_ref()
+                        .map(|s| s.display().to_string()),
+                })
+                .collect();
+            println!("{}", json::encode(&infos)
+                .map_err(|e| format!("Error encoding json: {}", e))?);
+        } else {
+            for (cname, container) in config.containers.iter() {
+                println!("{}", cname);
+                if let Some(ref src) = container.source {
+                    if verbose {
+                        println!("{:19} (from {:?})", " ", &src);
+                    }
                 }
             }
         }
@@ -105,6 +137,25 @@ pub fn print_list(config: &Config, mut args: Vec<String>)
             in this project".to_string(),
             &builtins));
 
+        if format == Format::Json {
+            let infos: Vec<_> = commands.iter()
+                .map(|&(name, ref description, source)| CommandInfo {
+                    name: name.to_string(),
+                    description: description.clone(),
+                    builtin: source.as_ref()
+                        .map_or(false, |s| &s[..] == "<builtins>"),
+                    hidden: name.starts_with("_"),
+                    source: source.as_ref().map(|s| s.to_string()),
+                    containers: config.commands.get(name)
+                        .map(|cmd| cmd.containers())
+                        .unwrap_or_else(Vec::new),
+                })
+                .collect();
+            println!("{}", json::encode(&infos)
+                .map_err(|e| format!("Error encoding json: {}", e))?);
+            return Ok(0);
+        }
+
         let mut out = stdout();
         for (name, description, source) in commands {
             if name.starts_with("_") && !(hidden || all) {
@@ -144,7 +195,7 @@ pub fn print_list(config: &Config, mut args: Vec<String>)
                         }
                     }
                 }
-                Format::Sphinx => unreachable!(),
+                Format::Sphinx | Format::Json => unreachable!(),
             }
         }
     }